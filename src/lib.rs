@@ -1,7 +1,8 @@
 //! # `ffi-pool`: useful object pool types for FFI code
 //!
-//! This crate contains some useful object pool types for interfacing with C code (at the moment,
-//! just `CStringPool`.)
+//! This crate contains some useful object pool types for interfacing with C code: `CStringPool`
+//! for individual `CString`s, and `CStrArrayPool` for nul-terminated, `argv`-style arrays of
+//! `CString`s.
 
 
 #[cfg(test)]
@@ -15,6 +16,8 @@ extern crate take_mut;
 use std::error::Error;
 use std::ffi::{CStr, CString};
 use std::fmt;
+use std::os::raw::c_char;
+use std::ptr;
 use std::sync::Arc;
 
 use objpool::{Item, Pool};
@@ -40,6 +43,41 @@ impl Error for NulError {
 }
 
 
+/// An error returned when constructing a `CString` from a buffer that is expected to already end
+/// with a single, trailing nul terminator.
+#[derive(Debug, Clone, Copy)]
+pub enum FromVecWithNulError {
+    /// An interior nul byte was found before the end of the buffer.
+    InteriorNul { position: usize },
+    /// The buffer did not end with a nul byte.
+    NotNulTerminated,
+}
+
+
+impl fmt::Display for FromVecWithNulError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FromVecWithNulError::InteriorNul { position } => {
+                write!(f, "interior nul byte found in provided data at position: {}", position)
+            }
+            FromVecWithNulError::NotNulTerminated => {
+                write!(f, "provided data is missing a trailing nul terminator")
+            }
+        }
+    }
+}
+
+
+impl Error for FromVecWithNulError {
+    fn description(&self) -> &str {
+        match *self {
+            FromVecWithNulError::InteriorNul { .. } => "interior nul byte found in data",
+            FromVecWithNulError::NotNulTerminated => "data is missing a trailing nul terminator",
+        }
+    }
+}
+
+
 /// A thread-safe pool of `CString`s which can be readily reused with `str`s for ease of FFI interactions.
 #[derive(Debug, Clone)]
 pub struct CStringPool {
@@ -87,16 +125,44 @@ impl CStringPool {
 
         let mut item = self.pool.get();
         take_mut::take(&mut *item, |cstring| {
-            // We are guaranteed that if a `CString` is in the pool, it is either empty or created from
-            // an `&str`. Thus, it is safe to convert as it *always* contains valid unicode data.
-            let mut string = unsafe { String::from_utf8_unchecked(cstring.into_bytes()) };
+            // We operate on the raw bytes rather than reinterpreting them as a `String`: a buffer
+            // pulled from the pool may have last held non-UTF-8 data inserted by `get_c_str` or
+            // `get_bytes`, so assuming it's valid unicode here would be unsound.
+            let mut bytes = cstring.into_bytes();
+
+            bytes.clear();
+            bytes.extend_from_slice(str_ref.as_bytes());
+
+            // We check for nul bytes outside of this block so that we can return an error instead of
+            // panicking.
+            unsafe { CString::from_vec_unchecked(bytes) }
+        });
+
+        Ok(item)
+    }
+
+
+    /// Allocate a new `CString` from the pool, using arbitrary bytes as a source. This will check
+    /// the supplied bytes for interior nul bytes. Unlike `get_str`, the input need not be valid
+    /// UTF-8, which makes this suitable for binary data such as file paths or serialized buffers.
+    pub fn get_bytes<T: AsRef<[u8]>>(&self, b: T) -> Result<Item<CString>, NulError> {
+        let bytes_ref = b.as_ref();
+
+        // Ensure our bytes contain no nul bytes and are thus safe to inject into a `CString`.
+        if let Some(i) = memchr::memchr(0, bytes_ref) {
+            return Err(NulError { position: i });
+        }
+
+        let mut item = self.pool.get();
+        take_mut::take(&mut *item, |cstring| {
+            let mut bytes = cstring.into_bytes();
 
-            string.clear();
-            string.push_str(str_ref);
+            bytes.clear();
+            bytes.extend_from_slice(bytes_ref);
 
             // We check for nul bytes outside of this block so that we can return an error instead of
             // panicking.
-            unsafe { CString::from_vec_unchecked(string.into_bytes()) }
+            unsafe { CString::from_vec_unchecked(bytes) }
         });
 
         Ok(item)
@@ -120,6 +186,156 @@ impl CStringPool {
 
         item
     }
+
+
+    /// Allocate a new `CString` from the pool, copying a nul-terminated C string out of a raw
+    /// pointer. This follows the same semantics as `CStr::from_ptr`: the bytes from `ptr` up to
+    /// (but not including) the first nul are copied into the returned, pool-owned `CString`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to a valid, nul-terminated C string, and the memory it points to must not
+    /// be mutated or freed for the duration of this call.
+    pub unsafe fn get_from_ptr(&self, ptr: *const c_char) -> Item<CString> {
+        self.get_c_str(CStr::from_ptr(ptr))
+    }
+
+
+    /// Allocate a new `CString` from the pool, using a buffer that already ends with a single,
+    /// trailing nul terminator. This checks that `b` contains exactly one nul byte, and that it is
+    /// the last byte in the buffer, before accepting it.
+    pub fn get_with_nul<T: AsRef<[u8]>>(&self, b: T) -> Result<Item<CString>, FromVecWithNulError> {
+        let bytes_ref = b.as_ref();
+
+        match memchr::memchr(0, bytes_ref) {
+            Some(i) if i + 1 == bytes_ref.len() => {}
+            Some(i) => return Err(FromVecWithNulError::InteriorNul { position: i }),
+            None => return Err(FromVecWithNulError::NotNulTerminated),
+        }
+
+        // We just verified that `bytes_ref` contains exactly one nul byte, positioned as its last
+        // byte, so this upholds the contract of `get_with_nul_unchecked`.
+        Ok(unsafe { self.get_with_nul_unchecked(bytes_ref) })
+    }
+
+
+    /// Allocate a new `CString` from the pool, using a buffer that already ends with a single,
+    /// trailing nul terminator, without checking that this is the case.
+    ///
+    /// # Safety
+    ///
+    /// `b` must contain exactly one nul byte, positioned as the final byte of the buffer.
+    pub unsafe fn get_with_nul_unchecked<T: AsRef<[u8]>>(&self, b: T) -> Item<CString> {
+        let bytes_ref = b.as_ref();
+        let without_nul = &bytes_ref[..bytes_ref.len() - 1];
+
+        let mut item = self.pool.get();
+        take_mut::take(&mut *item, |cstring| {
+            let mut bytes = cstring.into_bytes();
+
+            bytes.clear();
+            bytes.extend_from_slice(without_nul);
+
+            // The caller has guaranteed that `without_nul` contains no nul bytes.
+            CString::from_vec_unchecked(bytes)
+        });
+
+        item
+    }
+}
+
+
+/// A guard returned from `CStrArrayPool::get`, borrowing a pooled, nul-terminated array of
+/// `*const c_char` pointers suitable for passing to C APIs expecting a `char *const *` (e.g.
+/// `argv`-style arguments).
+///
+/// The individual `CString` buffers backing the pointers are kept alive for as long as this guard
+/// exists. On drop, both the strings and the pointer array are returned to their respective pools.
+#[derive(Debug)]
+pub struct CStrArrayItem {
+    strings: Vec<Item<CString>>,
+    pointers: Item<Vec<*const c_char>>,
+}
+
+
+impl CStrArrayItem {
+    /// Get a raw pointer to the nul-terminated array of `*const c_char` pointers. The pointer is
+    /// valid for as long as this `CStrArrayItem` is alive.
+    pub fn as_ptr(&self) -> *const *const c_char {
+        self.pointers.as_ptr()
+    }
+}
+
+
+/// A thread-safe pool of reusable, nul-terminated `argv`-style pointer arrays, for FFI calls that
+/// accept a `char *const *` (exec-family calls, library init functions, option lists, and so on).
+/// Pools both the individual `CString` buffers and the backing pointer `Vec`, amortizing what
+/// would otherwise be an allocation-heavy call.
+#[derive(Debug, Clone)]
+pub struct CStrArrayPool {
+    strings: CStringPool,
+    pointers: Arc<Pool<Vec<*const c_char>>>,
+}
+
+
+// The pooled pointer arrays are never dereferenced by the pool: they are cleared and refilled
+// before each use, and only handed out inside a `CStrArrayItem` that owns the strings they point
+// into. The raw pointers carry no thread affinity, so sharing the pool between threads is sound.
+unsafe impl Send for CStrArrayPool {}
+unsafe impl Sync for CStrArrayPool {}
+
+
+impl CStrArrayPool {
+    /// Create a new pool with given default capacities for newly allocated `CString`s and pointer
+    /// arrays.
+    pub fn new(default_string_capacity: usize, default_array_capacity: usize) -> CStrArrayPool {
+        CStrArrayPool {
+            strings: CStringPool::new(default_string_capacity),
+            pointers: Pool::new(move || Vec::with_capacity(default_array_capacity)),
+        }
+    }
+
+
+    /// Create a new pool with an additional maximum capacity. Allocating new `CString`s or pointer
+    /// arrays when the pool is at capacity will block until one is available.
+    pub fn with_capacity(
+        pool_capacity: usize,
+        default_string_capacity: usize,
+        default_array_capacity: usize,
+    ) -> CStrArrayPool {
+        CStrArrayPool {
+            strings: CStringPool::with_capacity(pool_capacity, default_string_capacity),
+            pointers: Pool::with_capacity(pool_capacity, move || {
+                Vec::with_capacity(default_array_capacity)
+            }),
+        }
+    }
+
+
+    /// Build a pooled, nul-terminated array of `*const c_char` from an iterator of byte-like
+    /// items. This will check each item for interior nul bytes, just as `CStringPool::get_bytes`
+    /// does.
+    pub fn get<I, T>(&self, items: I) -> Result<CStrArrayItem, NulError>
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<[u8]>,
+    {
+        let mut strings = Vec::new();
+        for item in items {
+            strings.push(self.strings.get_bytes(item)?);
+        }
+
+        let mut pointers = self.pointers.get();
+        take_mut::take(&mut *pointers, |mut vec| {
+            vec.clear();
+            vec.extend(strings.iter().map(|s| s.as_ptr()));
+            vec.push(ptr::null());
+
+            vec
+        });
+
+        Ok(CStrArrayItem { strings, pointers })
+    }
 }
 
 
@@ -146,4 +362,89 @@ mod tests {
         let s = "fo\0o";
         let _cstr = POOL.get_str(s).unwrap();
     }
+
+
+    #[test]
+    fn get_str_after_non_utf8_get_c_str() {
+        // A pool of capacity 1 guarantees the same underlying buffer is reused below, so this
+        // reproduces the soundness hole where `get_str` used to assume every pooled buffer held
+        // valid UTF-8.
+        let pool = CStringPool::with_capacity(1, 128);
+
+        let non_utf8 = CString::new(vec![0xff, 0xfe, 0xfd]).unwrap();
+        {
+            let _cstr = pool.get_c_str(&non_utf8);
+        }
+
+        let s = "foo";
+        let cstr = pool.get_str(s).unwrap();
+
+        assert_eq!(cstr.to_str().unwrap(), s);
+    }
+
+
+    #[test]
+    fn from_ptr() {
+        let source = CString::new("bar").unwrap();
+        let cstr = unsafe { POOL.get_from_ptr(source.as_ptr()) };
+
+        assert_eq!(cstr.to_str().unwrap(), "bar");
+    }
+
+
+    #[test]
+    fn with_nul() {
+        let cstr = POOL.get_with_nul(b"foo\0".to_vec()).unwrap();
+
+        assert_eq!(cstr.to_str().unwrap(), "foo");
+    }
+
+
+    #[test]
+    fn with_nul_interior() {
+        let err = POOL.get_with_nul(b"fo\0o\0".to_vec()).unwrap_err();
+
+        match err {
+            FromVecWithNulError::InteriorNul { position } => assert_eq!(position, 2),
+            FromVecWithNulError::NotNulTerminated => panic!("expected an interior nul error"),
+        }
+    }
+
+
+    #[test]
+    fn with_nul_missing_terminator() {
+        let err = POOL.get_with_nul(b"foo".to_vec()).unwrap_err();
+
+        match err {
+            FromVecWithNulError::NotNulTerminated => {}
+            FromVecWithNulError::InteriorNul { .. } => panic!("expected a missing-terminator error"),
+        }
+    }
+
+
+    lazy_static! {
+        static ref ARRAY_POOL: CStrArrayPool = CStrArrayPool::new(128, 8);
+    }
+
+
+    #[test]
+    fn array_round_trip() {
+        let array = ARRAY_POOL.get(&["foo", "bar", "baz"]).unwrap();
+        let ptr = array.as_ptr();
+
+        unsafe {
+            assert_eq!(CStr::from_ptr(*ptr.offset(0)).to_str().unwrap(), "foo");
+            assert_eq!(CStr::from_ptr(*ptr.offset(1)).to_str().unwrap(), "bar");
+            assert_eq!(CStr::from_ptr(*ptr.offset(2)).to_str().unwrap(), "baz");
+            assert!(ptr.offset(3).read().is_null());
+        }
+    }
+
+
+    #[test]
+    fn array_bad_string() {
+        let err = ARRAY_POOL.get(&["foo", "b\0ar"]).unwrap_err();
+
+        assert_eq!(err.position, 1);
+    }
 }